@@ -0,0 +1,2291 @@
+use nyst::Parser;
+use regex::Regex;
+use ron::{from_str, to_string};
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::rc::Rc;
+
+/// One step of `ParserExt::seq_with`: a parser, and a function that maps its result with access
+/// to the accumulator.
+pub type SeqStep<A> = (
+    Parser,
+    Box<dyn Fn(&mut A, String) -> Result<String, ron::Error>>,
+);
+
+/// What `ParserExt::key_values` does when a key appears more than once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicateKeys {
+    /// Keep the first value.
+    FirstWins,
+    /// Keep the last value, at the position of the first.
+    LastWins,
+    /// Fail parsing.
+    Error,
+}
+
+/// A line of an outline parsed by `ParserExt::outline`, with the lines indented under it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutlineNode {
+    pub text: String,
+    pub children: Vec<OutlineNode>,
+}
+
+/// Extra combinators built on top of `nyst::Parser`.
+///
+/// Like the parsers in `nyst`, a failed parse returns the original input as the error.
+///
+/// `Parser::repeat_range` belongs to nyst, so it still loops on a subparser that matches without
+/// consuming any input. Wrap such a subparser in `min_consumed`, or use `many0` or `many1`.
+///
+/// nyst doesn't expose whether a parser is ignored, so the combinators here that return a RON
+/// array of results, like `and_one_of`, can't drop ignored parsers the way `Parser::and` does.
+/// An ignored parser gives `""` in the array instead.
+pub trait ParserExt {
+    /// Create a parser that splits all of the remaining input on a delimiter.
+    ///
+    /// Empty pieces, including the ones before a leading delimiter or after a trailing delimiter,
+    /// are dropped. The result is a RON array of the pieces and the remaining text is always empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `delimiter` - The delimiter as a regular expression.
+    fn split_on<T: Into<String>>(delimiter: T) -> Parser;
+
+    /// Create a parser that fails if the subparser succeeds without consuming any input.
+    ///
+    /// Wrapping the subparser of a `repeat_range` with this ends the repetition at the first
+    /// empty match instead of looping until the maximum number of iterations is reached.
+    fn min_consumed(self) -> Parser;
+
+    /// Create a parser that repeats the subparser zero or more times.
+    ///
+    /// The subparser is wrapped in `min_consumed`, so an empty match ends the repetition, unlike
+    /// with `Parser::repeat_range` (see `ParserExt`).
+    fn many0(self) -> Parser;
+
+    /// Create a parser that repeats the subparser one or more times.
+    ///
+    /// The subparser is wrapped in `min_consumed`, so an empty match ends the repetition, unlike
+    /// with `Parser::repeat_range` (see `ParserExt`).
+    fn many1(self) -> Parser;
+
+    /// Create a parser that sums the numbers matched by a repeating subparser.
+    ///
+    /// Each element of the subparser's RON array is parsed as an `f64`, and the total is returned.
+    /// If any element is not a finite number, including `inf` and `NaN`, or the total overflows,
+    /// fail parsing.
+    fn sum(self) -> Parser;
+
+    /// Create a parser that matches the longest of a set of literal strings.
+    ///
+    /// # Arguments
+    ///
+    /// * `literals` - The literal strings to choose from.
+    fn one_of_literals(literals: &[&str]) -> Parser;
+
+    /// Create a parser that skips a leading UTF-8 byte order mark before parsing the subparser.
+    ///
+    /// Input without a byte order mark is passed to the subparser unchanged.
+    fn strip_bom(self) -> Parser;
+
+    /// Create a parser that parses a shared prefix once, then tries each alternative after it.
+    ///
+    /// This behaves like `self.and(a).or(self.and(b))`, but the prefix is only parsed once no
+    /// matter how many alternatives fail. The result is a RON array of the prefix and the first
+    /// alternative that succeeded. Ignored parsers are kept in the array (see `ParserExt`).
+    ///
+    /// # Arguments
+    ///
+    /// * `alternatives` - The parsers to try after the prefix, in order.
+    fn and_one_of(self, alternatives: Vec<Parser>) -> Parser;
+
+    /// Create a parser that returns a placeholder instead of failing.
+    ///
+    /// If the subparser fails, the placeholder is returned without consuming any input, and the
+    /// error (the input the subparser failed on) is pushed onto `errors`.
+    ///
+    /// # Arguments
+    ///
+    /// * `placeholder` - The text to return when the subparser fails, such as `"<error>"`.
+    /// * `errors` - The list that failures are recorded in.
+    fn or_placeholder<T: Into<String>>(
+        self,
+        placeholder: T,
+        errors: Rc<RefCell<Vec<String>>>,
+    ) -> Parser;
+
+    /// Create a parser that matches a regular expression and deserializes its named captures.
+    ///
+    /// Each named capture that participated in the match becomes a string field of `T`. The
+    /// result is `T` serialized as RON. If the captures do not deserialize into `T`, fail parsing.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The regular expression, with a named capture for each field of `T`.
+    fn regex_to_struct<T>(pattern: &str) -> Parser
+    where
+        T: DeserializeOwned + Serialize;
+
+    /// Create a parser that returns the next `n` characters without consuming them.
+    ///
+    /// If fewer than `n` characters remain, the rest of the input is returned instead of failing,
+    /// so this parser always succeeds.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - The number of characters to look ahead.
+    fn peek_n(n: usize) -> Parser;
+
+    /// Create a parser that performs a computation on each element of the subparser's RON array.
+    ///
+    /// If the computation fails for any element, fail parsing.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfn` - The custom function to apply to each element.
+    fn map_each<F>(self, cfn: F) -> Parser
+    where
+        F: Fn(String) -> Result<String, ron::Error> + 'static;
+
+    /// Create a parser that chooses the next parser based on the subparser's result.
+    ///
+    /// The result is a RON array of both results, like `Parser::and`, except that ignored parsers
+    /// are kept (see `ParserExt`).
+    ///
+    /// # Arguments
+    ///
+    /// * `cfn` - The custom function that creates the next parser from the first result.
+    fn then_with<F>(self, cfn: F) -> Parser
+    where
+        F: Fn(String) -> Parser + 'static;
+
+    /// Create a new `ParserKind::Regex` parser that ignores case.
+    ///
+    /// The pattern is wrapped as `^(?i:pattern)`, so the match is anchored to the start of the
+    /// input and the case-insensitive flag cannot leak into or out of the pattern.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The regular expression in string form.
+    fn regex_ci<T: Into<String>>(pattern: T) -> Parser;
+
+    /// Create a parser that always fails.
+    ///
+    /// Like every other failure, this returns the original input, so an enclosing `Parser::or`
+    /// moves on to its right side.
+    fn fail() -> Parser;
+
+    /// Create a parser that always succeeds with a value, without consuming any input.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The text to return.
+    fn succeed<T: Into<String>>(value: T) -> Parser;
+
+    /// Create a parser that repeats the subparser for as long as a guard parser matches.
+    ///
+    /// The guard is checked before each iteration and never consumes input. When the guard
+    /// fails, or the subparser matches without consuming anything, stop and return all results.
+    /// If the subparser fails after the guard matched, fail parsing.
+    ///
+    /// # Arguments
+    ///
+    /// * `guard` - The parser to look ahead with.
+    fn repeat_while(self, guard: Parser) -> Parser;
+
+    /// Create a parser that parses a block of lines that share the same indentation.
+    ///
+    /// The indentation of the first line, its leading spaces and tabs, sets the indentation of the
+    /// block, and each line with the same indentation is parsed with `item` after it. `item` may
+    /// consume more than one line, which is how nested blocks are written, but it must end at the
+    /// end of a line. The block stops at the first line whose indentation is a shorter prefix of
+    /// the block's. Lines that are empty or only contain spaces and tabs are skipped, and don't
+    /// count towards the indentation of the block.
+    ///
+    /// If a line is indented more than the block, or with different spaces and tabs, fail parsing.
+    /// A nested block has to start with the indentation of the block that contains it, followed
+    /// by more, so a tab is never the same as some number of spaces.
+    ///
+    /// # Arguments
+    ///
+    /// * `item` - The parser for each line of the block.
+    fn indent_block(item: Parser) -> Parser;
+
+    /// Create a parser that returns whether the subparser matched.
+    ///
+    /// If the subparser succeeds, return `"true"` and consume its match.
+    /// If the subparser fails, return `"false"` without consuming any input.
+    fn present(self) -> Parser;
+
+    /// Create a parser that matches a bracketed group and returns the text inside it.
+    ///
+    /// The input has to start with `(`, `[` or `{`, and is read until the matching closing
+    /// bracket. All three kinds of bracket may be nested inside each other. If a closing bracket
+    /// does not match the innermost open bracket, or the input ends first, fail parsing.
+    fn balanced_brackets() -> Parser;
+
+    /// Parse a string with every parser and return the results of all the ones that succeeded.
+    ///
+    /// Unlike `Parser::or`, every parser is tried, so more than one result means the input is
+    /// ambiguous.
+    ///
+    /// # Arguments
+    ///
+    /// * `parsers` - The alternatives to try.
+    /// * `src` - The string to be parsed.
+    fn all_matches<T: Into<String>>(parsers: Vec<Parser>, src: T) -> Vec<(String, String)>;
+
+    /// Create a parser that skips the preamble of a document before parsing the subparser.
+    ///
+    /// The preamble is an optional UTF-8 byte order mark followed by any amount of whitespace.
+    ///
+    /// # Arguments
+    ///
+    /// * `whitespace` - A regular expression matching one whitespace character, like `[ \n\r\t]`.
+    /// * `bom` - Whether to skip a leading byte order mark.
+    fn skip_preamble<T: Into<String>>(self, whitespace: T, bom: bool) -> Parser;
+
+    /// Parse a string and deserialize the matched RON into a `T`.
+    ///
+    /// A result that isn't valid RON, like the text matched by `Parser::regex`, is deserialized
+    /// as a string. The remaining text is discarded. If parsing or deserialization fails, return
+    /// the original input as the error.
+    ///
+    /// # Arguments
+    ///
+    /// * `src` - The string to be parsed.
+    fn parse_as<T: DeserializeOwned, S: Into<String>>(&self, src: S) -> Result<T, String>;
+
+    /// Create a parser that repeats the subparser like `many0`, up to a hard maximum.
+    ///
+    /// Unlike `Parser::repeat_range(0..max)`, which stops after `max` iterations and leaves the
+    /// rest of the input unconsumed, this fails parsing if the subparser would match again after
+    /// `max` iterations. This bounds the work done on pathological input.
+    ///
+    /// # Arguments
+    ///
+    /// * `max` - The most iterations allowed.
+    fn with_max_repeat(self, max: usize) -> Parser;
+
+    /// Create a parser that parses zero or more of the subparser, separated by `sep`.
+    ///
+    /// The result is a RON array of the subparser's results; the separators are discarded.
+    /// A trailing separator is not consumed.
+    ///
+    /// # Arguments
+    ///
+    /// * `sep` - The separator parser.
+    fn separated_by0(self, sep: Parser) -> Parser;
+
+    /// Create a parser that parses one or more of the subparser, separated by `sep`.
+    ///
+    /// Like `separated_by0`, but if there is not at least one match, fail parsing.
+    ///
+    /// # Arguments
+    ///
+    /// * `sep` - The separator parser.
+    fn separated_by1(self, sep: Parser) -> Parser;
+
+    /// Create a parser that names the elements of the subparser's RON array.
+    ///
+    /// The result is a RON map from each name to the element at the same position, in order.
+    /// If the array does not have exactly one element per name, fail parsing.
+    ///
+    /// The array has to be flat, like the result of `many0` or `separated_by1`. Chains of
+    /// `Parser::and` nest, so `a.and(b).and(c)` gives `[["a","b"],"c"]`, which has two elements.
+    ///
+    /// # Arguments
+    ///
+    /// * `names` - The field name of each element.
+    fn fields(self, names: &[&str]) -> Parser;
+
+    /// Create a parser that skips forward to the first position where the subparser matches.
+    ///
+    /// The skipped text is consumed along with the match. If the subparser does not match
+    /// anywhere in the input, fail parsing.
+    ///
+    /// # Arguments
+    ///
+    /// * `keep_skipped` - Whether to return a RON array of the skipped text and the match,
+    ///   instead of only the match.
+    fn search(self, keep_skipped: bool) -> Parser;
+
+    /// Create a parser that collects every match of a regular expression in the remaining input.
+    ///
+    /// Matches are found from left to right and do not overlap: searching resumes after the end
+    /// of each match. The result is a RON array of the matches, and the remaining text is always
+    /// empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The regular expression in string form.
+    fn regex_find_all<T: Into<String>>(pattern: T) -> Parser;
+
+    /// Create a parser that parses a sequence of steps while threading an accumulator through them.
+    ///
+    /// Each step's result is mapped by its function, which gets mutable access to the
+    /// accumulator, so later steps can depend on earlier ones. The accumulator starts from a clone
+    /// of `init` on every parse. The result is a RON array of the mapped results. If any step or
+    /// function fails, fail parsing.
+    ///
+    /// # Arguments
+    ///
+    /// * `init` - The initial value of the accumulator.
+    /// * `steps` - The parsers to run in order, each with its mapping function.
+    fn seq_with<A: Clone + 'static>(init: A, steps: Vec<SeqStep<A>>) -> Parser;
+
+    /// Create a parser that fails if the text consumed by the subparser has a disallowed character.
+    ///
+    /// The consumed input is checked rather than the result, because results like RON arrays
+    /// escape control characters.
+    ///
+    /// # Arguments
+    ///
+    /// * `disallowed` - Returns whether a character is disallowed, like `char::is_control`.
+    fn deny_chars<F>(self, disallowed: F) -> Parser
+    where
+        F: Fn(char) -> bool + 'static;
+
+    /// Create a parser that removes duplicates from the subparser's RON array.
+    ///
+    /// Elements are compared as exact strings, and the first occurrence of each is kept in order.
+    fn distinct(self) -> Parser;
+
+    /// Create a parser that converts `\r\n` and lone `\r` line endings to `\n` for the subparser.
+    ///
+    /// The result is the subparser's result on the normalized input, but the remaining text and
+    /// errors are taken from the original input, so offsets computed from them still refer to the
+    /// original source.
+    fn normalize_newlines(self) -> Parser;
+
+    /// Create a parser that only runs the subparser if a cheaper guard parser matches first.
+    ///
+    /// The guard never consumes input; if it fails, fail parsing without running the subparser.
+    /// This saves work in long chains of `Parser::or` where each branch has a quick discriminator.
+    ///
+    /// # Arguments
+    ///
+    /// * `guard` - The parser to look ahead with, like a regex for the first character.
+    fn guarded_by(self, guard: Parser) -> Parser;
+
+    /// Parse a string and deserialize each element of the matched RON array into a `T`, like
+    /// `parse_as`.
+    ///
+    /// The remaining text is discarded. If parsing or deserializing any element fails, return
+    /// the original input as the error.
+    ///
+    /// # Arguments
+    ///
+    /// * `src` - The string to be parsed.
+    fn parse_list_as<T: DeserializeOwned, S: Into<String>>(&self, src: S)
+        -> Result<Vec<T>, String>;
+
+    /// Create a parser that matches all of the remaining input.
+    ///
+    /// This always succeeds, returning `""` on empty input.
+    fn take_rest() -> Parser;
+
+    /// Create a parser that repeats the subparser like `Parser::repeat_range`, requiring progress.
+    ///
+    /// If an iteration succeeds but consumes fewer than `min_bytes` bytes, fail parsing instead of
+    /// continuing to repeat.
+    ///
+    /// # Arguments
+    ///
+    /// * `num_repeats` - The range of possible iterations.
+    /// * `min_bytes` - The least number of bytes each iteration has to consume.
+    fn repeat_min_progress(self, num_repeats: Range<usize>, min_bytes: usize) -> Parser;
+
+    /// Create a parser that parses the subparser's result again with another parser.
+    ///
+    /// The result is the inner parser's result. If the inner parser does not consume all of the
+    /// subparser's result, fail parsing.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The parser to parse the subparser's result with.
+    fn reparse(self, inner: Parser) -> Parser;
+
+    /// Create a parser that parses every line of the remaining input with `line`.
+    ///
+    /// Lines are split on `\n` or `\r\n`, and a final line ending does not start another line.
+    /// `line` has to consume each line completely. The result is a RON array of each line's
+    /// result, and the remaining text is always empty. If any line fails, fail parsing; use
+    /// `parse_lines` to find out which line it was.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The parser for each line.
+    fn lines(line: Parser) -> Parser;
+
+    /// Parse every line of a string with this parser, like `Parser::lines`.
+    ///
+    /// If a line fails, return its index (starting from 0) as the error.
+    ///
+    /// # Arguments
+    ///
+    /// * `src` - The string to be parsed.
+    fn parse_lines<T: Into<String>>(&self, src: T) -> Result<Vec<String>, usize>;
+
+    /// Create a parser that converts the subparser's result to lowercase.
+    ///
+    /// This uses Unicode case mapping, like `str::to_lowercase`.
+    fn to_lowercase(self) -> Parser;
+
+    /// Create a parser that converts the subparser's result to uppercase.
+    ///
+    /// This uses Unicode case mapping, like `str::to_uppercase`.
+    fn to_uppercase(self) -> Parser;
+
+    /// Create a parser like `separated_by1` where every separator has to be the same.
+    ///
+    /// Separators are compared by the text they consume, so they can be ignored parsers like
+    /// `Parser::regex("[,;]").ignore()`. If a separator differs from the first one, fail parsing, so that lists like `1,2;3` are rejected instead of stopping at the `;`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sep` - The separator parser.
+    fn separated_by_uniform(self, sep: Parser) -> Parser;
+
+    /// Create a parser that prefixes each element of the subparser's RON array with its index.
+    ///
+    /// The elements become `"0:a"`, `"1:b"`, and so on.
+    fn indexed(self) -> Parser;
+
+    /// Create a parser that fails if the subparser consumes fewer than `n` bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - The least number of bytes to consume.
+    fn min_len(self, n: usize) -> Parser;
+
+    /// Create a parser that fails if the subparser consumes more than `n` bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - The most bytes to consume.
+    fn max_len(self, n: usize) -> Parser;
+
+    /// Parse each of a list of strings, like calling `parse` on each one.
+    ///
+    /// # Arguments
+    ///
+    /// * `inputs` - The strings to be parsed.
+    fn parse_many<I, T>(&self, inputs: I) -> Vec<Result<(String, String), String>>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>;
+
+    /// Create a parser that parses one comma-separated row, like a line of a CSV file.
+    ///
+    /// Fields may be wrapped in double quotes, in which case they can contain commas and line
+    /// endings, and `""` stands for one `"`. The row ends at a line ending, which is consumed, or
+    /// at the end of the input. The result is a RON array of the unquoted fields. If a quoted field
+    /// is not closed, or is followed by anything other than a comma or the end of the row, fail
+    /// parsing.
+    fn csv_row() -> Parser;
+
+    /// Create a parser like `Parser::and` that requires whitespace between the two parsers.
+    ///
+    /// The whitespace is discarded. If there is no whitespace between them, fail parsing. Unlike
+    /// with `Parser::and`, ignored parsers are kept (see `ParserExt`).
+    ///
+    /// # Arguments
+    ///
+    /// * `r` - The right side parser.
+    fn and_ws(self, r: Parser) -> Parser;
+
+    /// Create a parser that remembers where the subparser failed and doesn't try it there again.
+    ///
+    /// Failures are cached by position in the input, so backtracking through `Parser::or` only
+    /// attempts the subparser once at each position. The cache holds one copy of the input and
+    /// the positions where the subparser failed, and is cleared when the parser is given text
+    /// that isn't part of that input, like on the next call to `parse`. The cache is only correct
+    /// for subparsers that give the same result for the same input, so don't use it around
+    /// `Counters` or `indent_block`.
+    fn cache_failures(self) -> Parser;
+
+    /// Create a parser that collects key-value pairs into a RON map, keeping their order.
+    ///
+    /// The subparser has to return a RON array of a key and a value, like
+    /// `key.and(Parser::regex("=").ignore()).and(value)`. Pairs are separated by `sep`, as in
+    /// `separated_by1`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sep` - The separator between pairs.
+    /// * `duplicates` - What to do when a key appears more than once.
+    fn key_values(self, sep: Parser, duplicates: DuplicateKeys) -> Parser;
+
+    /// Create a parser that removes matching quotes from around the subparser's result.
+    ///
+    /// If the result starts and ends with the same `"` or `'`, the inner text is returned.
+    /// Otherwise, the result is returned unchanged.
+    fn unquote(self) -> Parser;
+
+    /// Create a parser that parses the whole input with the subparser, or else with a fallback.
+    ///
+    /// Unlike `Parser::or`, a result only counts if it consumes all of the input, so a primary
+    /// grammar that matches a prefix of the input does not hide the fallback. If neither parser
+    /// consumes all of the input, fail parsing.
+    ///
+    /// # Arguments
+    ///
+    /// * `fallback` - The parser to use when the subparser can't parse the whole input.
+    fn or_fallback(self, fallback: Parser) -> Parser;
+
+    /// Create a parser that parses an indented outline into a tree of `OutlineNode`s.
+    ///
+    /// Each line is parsed with `line` after its indentation, and the lines indented under it
+    /// (as in `indent_block`) become its children. Blank lines are skipped. The result is a RON
+    /// array of the top level nodes, which deserializes into a `Vec<OutlineNode>`.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The parser for the text of each line, like `Parser::regex(r"[^\n]+")`.
+    fn outline(line: Parser) -> Parser;
+}
+impl ParserExt for Parser {
+    fn split_on<T: Into<String>>(delimiter: T) -> Parser {
+        let re = Regex::new(&delimiter.into()).expect("could not compile regex");
+        Parser::custom(move |s| {
+            let pieces = re
+                .split(&s)
+                .filter(|piece| !piece.is_empty())
+                .collect::<Vec<&str>>();
+            Ok((to_string(&pieces).unwrap(), "".into()))
+        })
+    }
+
+    fn min_consumed(self) -> Parser {
+        Parser::custom(move |s| match self.parse(s.clone()) {
+            Ok((_, rest)) if rest.len() == s.len() => Err(s),
+            result => result,
+        })
+    }
+
+    fn many0(self) -> Parser {
+        self.min_consumed().repeat_range(0..usize::MAX)
+    }
+
+    fn many1(self) -> Parser {
+        self.min_consumed().repeat_range(1..usize::MAX)
+    }
+
+    fn sum(self) -> Parser {
+        try_map(self, |matched| {
+            let mut total = 0.0;
+            for element in from_str::<Vec<String>>(&matched)? {
+                // `f64::from_str` also accepts "inf" and "NaN", which aren't numbers here.
+                total += match element.parse::<f64>() {
+                    Ok(number) if number.is_finite() => number,
+                    _ => return Err(ron::Error::custom(format!("not a number: {:?}", element))),
+                };
+            }
+            if !total.is_finite() {
+                return Err(ron::Error::custom("sum overflowed"));
+            }
+            Ok(total.to_string())
+        })
+    }
+
+    fn one_of_literals(literals: &[&str]) -> Parser {
+        let mut literals = literals
+            .iter()
+            .map(|literal| literal.to_string())
+            .collect::<Vec<String>>();
+        // Try the longest literals first so that "<=" wins over "<".
+        literals.sort_by_key(|literal| std::cmp::Reverse(literal.len()));
+        Parser::custom(
+            move |s| match literals.iter().find(|l| s.starts_with(&l[..])) {
+                Some(literal) => Ok((literal.clone(), s[literal.len()..].to_owned())),
+                None => Err(s),
+            },
+        )
+    }
+
+    fn strip_bom(self) -> Parser {
+        Parser::custom(move |s| {
+            let stripped = s.strip_prefix('\u{FEFF}').unwrap_or(&s);
+            self.parse(stripped).map_err(|_| s.clone())
+        })
+    }
+
+    fn and_one_of(self, alternatives: Vec<Parser>) -> Parser {
+        Parser::custom(move |s| {
+            let (prefix, rest) = self.parse(s.clone()).map_err(|_| s.clone())?;
+            for alternative in &alternatives {
+                if let Ok((matched, rest)) = alternative.parse(rest.clone()) {
+                    return Ok((to_string(&vec![prefix, matched]).unwrap(), rest));
+                }
+            }
+            Err(s)
+        })
+    }
+
+    fn or_placeholder<T: Into<String>>(
+        self,
+        placeholder: T,
+        errors: Rc<RefCell<Vec<String>>>,
+    ) -> Parser {
+        let placeholder: String = placeholder.into();
+        Parser::custom(move |s| match self.parse(s.clone()) {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                errors.borrow_mut().push(e);
+                Ok((placeholder.clone(), s))
+            }
+        })
+    }
+
+    fn regex_to_struct<T>(pattern: &str) -> Parser
+    where
+        T: DeserializeOwned + Serialize,
+    {
+        let re = Regex::new(&format!("^(?:{})", pattern)).expect("could not compile regex");
+        Parser::custom(move |s| {
+            let captures = match re.captures(&s) {
+                Some(captures) => captures,
+                None => return Err(s),
+            };
+            // Build a RON struct out of the captures, like `(y:"2020",m:"10",d:"14")`.
+            let mut fields = vec![];
+            for name in re.capture_names().flatten() {
+                if let Some(value) = captures.name(name) {
+                    fields.push(format!("{}:{}", name, to_string(&value.as_str()).unwrap()));
+                }
+            }
+            let value = match from_str::<T>(&format!("({})", fields.join(","))) {
+                Ok(value) => value,
+                Err(_) => return Err(s),
+            };
+            let end = captures.get(0).unwrap().end();
+            Ok((to_string(&value).unwrap(), s[end..].to_owned()))
+        })
+    }
+
+    fn peek_n(n: usize) -> Parser {
+        Parser::custom(move |s| Ok((s.chars().take(n).collect(), s)))
+    }
+
+    fn map_each<F>(self, cfn: F) -> Parser
+    where
+        F: Fn(String) -> Result<String, ron::Error> + 'static,
+    {
+        try_map(self, move |matched| {
+            let mut mapped = vec![];
+            for element in from_str::<Vec<String>>(&matched)? {
+                mapped.push(cfn(element)?);
+            }
+            to_string(&mapped)
+        })
+    }
+
+    fn then_with<F>(self, cfn: F) -> Parser
+    where
+        F: Fn(String) -> Parser + 'static,
+    {
+        Parser::custom(move |s| {
+            let (lmatched, lrest) = self.parse(s.clone()).map_err(|_| s.clone())?;
+            let (rmatched, rrest) = cfn(lmatched.clone()).parse(lrest).map_err(|_| s.clone())?;
+            Ok((to_string(&vec![lmatched, rmatched]).unwrap(), rrest))
+        })
+    }
+
+    fn regex_ci<T: Into<String>>(pattern: T) -> Parser {
+        Parser::regex(format!("^(?i:{})", pattern.into()))
+    }
+
+    fn fail() -> Parser {
+        Parser::custom(Err)
+    }
+
+    fn succeed<T: Into<String>>(value: T) -> Parser {
+        let value: String = value.into();
+        Parser::custom(move |s| Ok((value.clone(), s)))
+    }
+
+    fn repeat_while(self, guard: Parser) -> Parser {
+        Parser::custom(move |s| {
+            let mut matched = vec![];
+            let mut rest = s.clone();
+            while guard.parse(rest.clone()).is_ok() {
+                let (m, r) = self.parse(rest.clone()).map_err(|_| s.clone())?;
+                if r.len() == rest.len() {
+                    break;
+                }
+                matched.push(m);
+                rest = r;
+            }
+            Ok((to_string(&matched).unwrap(), rest))
+        })
+    }
+
+    fn indent_block(item: Parser) -> Parser {
+        Parser::custom(move |s| {
+            let indent = indentation(skip_blank_lines(&s)).to_owned();
+            let enclosing = INDENT_LEVELS.with(|levels| levels.borrow().last().cloned());
+            if enclosing.is_some_and(|enclosing| {
+                indent.len() <= enclosing.len() || !indent.starts_with(&enclosing)
+            }) {
+                return Err(s);
+            }
+            INDENT_LEVELS.with(|levels| levels.borrow_mut().push(indent.clone()));
+            let result = parse_indent_block(&item, s, &indent);
+            INDENT_LEVELS.with(|levels| levels.borrow_mut().pop());
+            result
+        })
+    }
+
+    fn present(self) -> Parser {
+        Parser::custom(move |s| match self.parse(s.clone()) {
+            Ok((_, rest)) => Ok(("true".into(), rest)),
+            Err(_) => Ok(("false".into(), s)),
+        })
+    }
+
+    fn balanced_brackets() -> Parser {
+        Parser::custom(|s| {
+            let mut open = vec![];
+            for (i, c) in s.char_indices() {
+                match c {
+                    '(' | '[' | '{' => open.push(c),
+                    ')' | ']' | '}' => {
+                        let expected = match open.pop() {
+                            Some('(') => ')',
+                            Some('[') => ']',
+                            Some(_) => '}',
+                            None => return Err(s),
+                        };
+                        if c != expected {
+                            return Err(s);
+                        }
+                    }
+                    _ if i == 0 => return Err(s),
+                    _ => {}
+                }
+                if open.is_empty() {
+                    return Ok((s[1..i].to_owned(), s[i + 1..].to_owned()));
+                }
+            }
+            Err(s)
+        })
+    }
+
+    fn all_matches<T: Into<String>>(parsers: Vec<Parser>, src: T) -> Vec<(String, String)> {
+        let s: String = src.into();
+        parsers
+            .iter()
+            .filter_map(|parser| parser.parse(s.clone()).ok())
+            .collect()
+    }
+
+    fn skip_preamble<T: Into<String>>(self, whitespace: T, bom: bool) -> Parser {
+        let whitespace =
+            Regex::new(&format!("^(?:{})*", whitespace.into())).expect("could not compile regex");
+        let parser = Parser::custom(move |s| {
+            let start = whitespace.find(&s).map_or(0, |mat| mat.end());
+            self.parse(&s[start..]).map_err(|_| s.clone())
+        });
+        if bom {
+            parser.strip_bom()
+        } else {
+            parser
+        }
+    }
+
+    fn parse_as<T: DeserializeOwned, S: Into<String>>(&self, src: S) -> Result<T, String> {
+        let s: String = src.into();
+        let (matched, _) = self.parse(s.clone()).map_err(|_| s.clone())?;
+        deserialize::<T>(&matched).map_err(|_| s)
+    }
+
+    fn with_max_repeat(self, max: usize) -> Parser {
+        let parser = self.min_consumed();
+        Parser::custom(move |s| {
+            let mut matched = vec![];
+            let mut rest = s.clone();
+            while let Ok((m, r)) = parser.parse(rest.clone()) {
+                if matched.len() == max {
+                    return Err(s);
+                }
+                matched.push(m);
+                rest = r;
+            }
+            Ok((to_string(&matched).unwrap(), rest))
+        })
+    }
+
+    fn separated_by0(self, sep: Parser) -> Parser {
+        let separated = self.separated_by1(sep);
+        Parser::custom(move |s| separated.parse(s.clone()).or(Ok(("[]".into(), s))))
+    }
+
+    fn separated_by1(self, sep: Parser) -> Parser {
+        Parser::custom(move |s| {
+            let (first, mut rest) = self.parse(s.clone()).map_err(|_| s.clone())?;
+            let mut matched = vec![first];
+            while let Ok((_, r)) = sep.parse(rest.clone()) {
+                match self.parse(r) {
+                    Ok((m, r)) if r.len() < rest.len() => {
+                        matched.push(m);
+                        rest = r;
+                    }
+                    _ => break,
+                }
+            }
+            Ok((to_string(&matched).unwrap(), rest))
+        })
+    }
+
+    fn fields(self, names: &[&str]) -> Parser {
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<String>>();
+        try_map(self, move |matched| {
+            let elements = from_str::<Vec<String>>(&matched)?;
+            if elements.len() != names.len() {
+                return Err(ron::Error::custom(format!(
+                    "expected {} fields, found {}",
+                    names.len(),
+                    elements.len()
+                )));
+            }
+            Ok(ron_map(names.iter().zip(elements.iter())))
+        })
+    }
+
+    fn search(self, keep_skipped: bool) -> Parser {
+        Parser::custom(move |s| {
+            let starts = s.char_indices().map(|(i, _)| i).chain(Some(s.len()));
+            for start in starts {
+                if let Ok((matched, rest)) = self.parse(&s[start..]) {
+                    if keep_skipped {
+                        let skipped = s[..start].to_owned();
+                        return Ok((to_string(&vec![skipped, matched]).unwrap(), rest));
+                    } else {
+                        return Ok((matched, rest));
+                    }
+                }
+            }
+            Err(s)
+        })
+    }
+
+    fn regex_find_all<T: Into<String>>(pattern: T) -> Parser {
+        let re = Regex::new(&pattern.into()).expect("could not compile regex");
+        Parser::custom(move |s| {
+            let matches = re
+                .find_iter(&s)
+                .map(|mat| mat.as_str())
+                .collect::<Vec<&str>>();
+            Ok((to_string(&matches).unwrap(), "".into()))
+        })
+    }
+
+    fn seq_with<A: Clone + 'static>(init: A, steps: Vec<SeqStep<A>>) -> Parser {
+        Parser::custom(move |s| {
+            let mut acc = init.clone();
+            let mut matched = vec![];
+            let mut rest = s.clone();
+            for (parser, cfn) in &steps {
+                let (m, r) = parser.parse(rest).map_err(|_| s.clone())?;
+                matched.push(cfn(&mut acc, m).map_err(|_| s.clone())?);
+                rest = r;
+            }
+            Ok((to_string(&matched).unwrap(), rest))
+        })
+    }
+
+    fn deny_chars<F>(self, disallowed: F) -> Parser
+    where
+        F: Fn(char) -> bool + 'static,
+    {
+        Parser::custom(move |s| {
+            let (matched, rest) = self.parse(s.clone()).map_err(|_| s.clone())?;
+            if s[..s.len() - rest.len()].chars().any(&disallowed) {
+                Err(s)
+            } else {
+                Ok((matched, rest))
+            }
+        })
+    }
+
+    fn distinct(self) -> Parser {
+        try_map(self, |matched| {
+            let mut elements = vec![];
+            for element in from_str::<Vec<String>>(&matched)? {
+                if !elements.contains(&element) {
+                    elements.push(element);
+                }
+            }
+            to_string(&elements)
+        })
+    }
+
+    fn normalize_newlines(self) -> Parser {
+        Parser::custom(move |s| {
+            let normalized = s.replace("\r\n", "\n").replace('\r', "\n");
+            let (matched, rest) = self.parse(normalized.clone()).map_err(|_| s.clone())?;
+            // Find where the consumed part of the normalized input ends in the original input.
+            let consumed = normalized.len() - rest.len();
+            let (mut original, mut normal) = (0, 0);
+            while normal < consumed {
+                if s[original..].starts_with("\r\n") {
+                    original += 2;
+                    normal += 1;
+                } else {
+                    let len = s[original..].chars().next().unwrap().len_utf8();
+                    original += len;
+                    normal += len;
+                }
+            }
+            Ok((matched, s[original..].to_owned()))
+        })
+    }
+
+    fn guarded_by(self, guard: Parser) -> Parser {
+        Parser::custom(move |s| {
+            if guard.parse(s.clone()).is_ok() {
+                self.parse(s.clone()).map_err(|_| s)
+            } else {
+                Err(s)
+            }
+        })
+    }
+
+    fn parse_list_as<T: DeserializeOwned, S: Into<String>>(
+        &self,
+        src: S,
+    ) -> Result<Vec<T>, String> {
+        let s: String = src.into();
+        let elements = self.parse_as::<Vec<String>, _>(s.clone())?;
+        elements
+            .iter()
+            .map(|element| deserialize::<T>(element))
+            .collect::<Result<Vec<T>, ron::Error>>()
+            .map_err(|_| s)
+    }
+
+    fn take_rest() -> Parser {
+        Parser::custom(|s| Ok((s, "".into())))
+    }
+
+    fn repeat_min_progress(self, num_repeats: Range<usize>, min_bytes: usize) -> Parser {
+        Parser::custom(move |s| {
+            let mut matched = vec![];
+            let mut rest = s.clone();
+            for i in 0..num_repeats.end {
+                let (m, r) = match self.parse(rest.clone()) {
+                    Ok(result) => result,
+                    Err(_) if i < num_repeats.start => return Err(s),
+                    Err(_) => break,
+                };
+                if rest.len() - r.len() < min_bytes {
+                    return Err(s);
+                }
+                matched.push(m);
+                rest = r;
+            }
+            Ok((to_string(&matched).unwrap(), rest))
+        })
+    }
+
+    fn reparse(self, inner: Parser) -> Parser {
+        Parser::custom(move |s| {
+            let (matched, rest) = self.parse(s.clone()).map_err(|_| s.clone())?;
+            match inner.parse(matched) {
+                Ok((m, r)) if r.is_empty() => Ok((m, rest)),
+                _ => Err(s),
+            }
+        })
+    }
+
+    fn lines(line: Parser) -> Parser {
+        Parser::custom(move |s| match line.parse_lines(s.clone()) {
+            Ok(matched) => Ok((to_string(&matched).unwrap(), "".into())),
+            Err(_) => Err(s),
+        })
+    }
+
+    fn parse_lines<T: Into<String>>(&self, src: T) -> Result<Vec<String>, usize> {
+        let s: String = src.into();
+        let mut matched = vec![];
+        for (i, line) in s.lines().enumerate() {
+            match self.parse(line) {
+                Ok((m, rest)) if rest.is_empty() => matched.push(m),
+                _ => return Err(i),
+            }
+        }
+        Ok(matched)
+    }
+
+    fn to_lowercase(self) -> Parser {
+        try_map(self, |matched| Ok(matched.to_lowercase()))
+    }
+
+    fn to_uppercase(self) -> Parser {
+        try_map(self, |matched| Ok(matched.to_uppercase()))
+    }
+
+    fn separated_by_uniform(self, sep: Parser) -> Parser {
+        Parser::custom(move |s| {
+            let (first, mut rest) = self.parse(s.clone()).map_err(|_| s.clone())?;
+            let mut matched = vec![first];
+            let mut separator = None;
+            while let Ok((_, sep_rest)) = sep.parse(rest.clone()) {
+                // Compare the consumed text, since ignored separators all give "".
+                let sep_text = rest[..rest.len() - sep_rest.len()].to_owned();
+                match self.parse(sep_rest) {
+                    Ok((m, r)) if r.len() < rest.len() => {
+                        if separator.get_or_insert_with(|| sep_text.clone()) != &sep_text {
+                            return Err(s);
+                        }
+                        matched.push(m);
+                        rest = r;
+                    }
+                    _ => break,
+                }
+            }
+            Ok((to_string(&matched).unwrap(), rest))
+        })
+    }
+
+    fn indexed(self) -> Parser {
+        try_map(self, |matched| {
+            let elements = from_str::<Vec<String>>(&matched)?
+                .iter()
+                .enumerate()
+                .map(|(i, element)| format!("{}:{}", i, element))
+                .collect::<Vec<String>>();
+            to_string(&elements)
+        })
+    }
+
+    fn min_len(self, n: usize) -> Parser {
+        Parser::custom(move |s| match self.parse(s.clone()) {
+            Ok((matched, rest)) if s.len() - rest.len() >= n => Ok((matched, rest)),
+            _ => Err(s),
+        })
+    }
+
+    fn max_len(self, n: usize) -> Parser {
+        Parser::custom(move |s| match self.parse(s.clone()) {
+            Ok((matched, rest)) if s.len() - rest.len() <= n => Ok((matched, rest)),
+            _ => Err(s),
+        })
+    }
+
+    fn parse_many<I, T>(&self, inputs: I) -> Vec<Result<(String, String), String>>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        inputs.into_iter().map(|src| self.parse(src)).collect()
+    }
+
+    fn csv_row() -> Parser {
+        Parser::custom(|s| {
+            let mut fields = vec![];
+            let mut field = String::new();
+            let mut chars = s.char_indices().peekable();
+            loop {
+                // Each iteration parses one field and the character after it.
+                if let Some((_, '"')) = chars.peek() {
+                    chars.next();
+                    loop {
+                        match chars.next() {
+                            Some((_, '"')) if chars.peek().map(|(_, c)| *c) == Some('"') => {
+                                chars.next();
+                                field.push('"');
+                            }
+                            Some((_, '"')) => break,
+                            Some((_, c)) => field.push(c),
+                            None => return Err(s),
+                        }
+                    }
+                } else {
+                    while let Some((_, c)) = chars.peek() {
+                        if *c == ',' || *c == '\n' || *c == '\r' {
+                            break;
+                        }
+                        field.push(*c);
+                        chars.next();
+                    }
+                }
+                fields.push(std::mem::take(&mut field));
+                let rest = match chars.next() {
+                    Some((_, ',')) => continue,
+                    Some((i, '\n')) => &s[i + 1..],
+                    Some((i, '\r')) if s[i..].starts_with("\r\n") => &s[i + 2..],
+                    Some(_) => return Err(s),
+                    None => "",
+                };
+                return Ok((to_string(&fields).unwrap(), rest.to_owned()));
+            }
+        })
+    }
+
+    fn and_ws(self, r: Parser) -> Parser {
+        let whitespace = Regex::new(r"^\s+").unwrap();
+        Parser::custom(move |s| {
+            let (lmatched, lrest) = self.parse(s.clone()).map_err(|_| s.clone())?;
+            let start = match whitespace.find(&lrest) {
+                Some(mat) => mat.end(),
+                None => return Err(s),
+            };
+            let (rmatched, rrest) = r.parse(&lrest[start..]).map_err(|_| s.clone())?;
+            Ok((to_string(&vec![lmatched, rmatched]).unwrap(), rrest))
+        })
+    }
+
+    fn cache_failures(self) -> Parser {
+        // The input being parsed, and the lengths of the remaining input where the subparser
+        // failed. Any remaining input that ends the input is identified by its length.
+        let cache = RefCell::new((String::new(), HashSet::new()));
+        Parser::custom(move |s| {
+            {
+                let (input, failures) = &mut *cache.borrow_mut();
+                if !input.ends_with(&s) {
+                    *input = s.clone();
+                    failures.clear();
+                } else if failures.contains(&s.len()) {
+                    return Err(s);
+                }
+            }
+            self.parse(s.clone()).map_err(|_| {
+                cache.borrow_mut().1.insert(s.len());
+                s
+            })
+        })
+    }
+
+    fn key_values(self, sep: Parser, duplicates: DuplicateKeys) -> Parser {
+        try_map(self.separated_by1(sep), move |matched| {
+            let mut entries: Vec<(String, String)> = vec![];
+            for pair in from_str::<Vec<String>>(&matched)? {
+                let (key, value) = deserialize::<(String, String)>(&pair)?;
+                match entries.iter_mut().find(|(k, _)| *k == key) {
+                    None => entries.push((key, value)),
+                    Some(_) if duplicates == DuplicateKeys::FirstWins => {}
+                    Some(entry) if duplicates == DuplicateKeys::LastWins => entry.1 = value,
+                    Some(_) => {
+                        return Err(ron::Error::custom(format!("duplicate key {:?}", key)));
+                    }
+                }
+            }
+            Ok(ron_map(entries.iter().map(|(key, value)| (key, value))))
+        })
+    }
+
+    fn unquote(self) -> Parser {
+        try_map(self, |matched| {
+            for quote in &['"', '\''] {
+                if matched.len() >= 2 && matched.starts_with(*quote) && matched.ends_with(*quote) {
+                    return Ok(matched[1..matched.len() - 1].to_owned());
+                }
+            }
+            Ok(matched)
+        })
+    }
+
+    fn or_fallback(self, fallback: Parser) -> Parser {
+        Parser::custom(move |s| {
+            for parser in &[&self, &fallback] {
+                match parser.parse(s.clone()) {
+                    Ok((matched, rest)) if rest.is_empty() => return Ok((matched, rest)),
+                    _ => {}
+                }
+            }
+            Err(s)
+        })
+    }
+
+    fn outline(line: Parser) -> Parser {
+        try_map(Parser::indent_block(outline_node(line)), |matched| {
+            to_string(&outline_nodes(&matched)?)
+        })
+    }
+}
+
+/// A table of named rules that are looked up at parse time.
+///
+/// Rules can call each other (or themselves) by name, so recursive grammars can be built at
+/// runtime. Clones of a table share the same rules. Inside rules, use `call`; to get the parser
+/// for the whole grammar, use `entry`, which keeps the table alive.
+///
+/// A rule that calls itself again at the same position, directly or through other rules, is left
+/// recursive and would recurse forever, so parsing panics with a message naming the rule.
+#[derive(Clone, Default)]
+pub struct RuleTable {
+    rules: Rc<RefCell<HashMap<String, Parser>>>,
+    // The rules being parsed, with the length of the remaining input they started at.
+    active: Rc<RefCell<HashSet<(String, usize)>>>,
+}
+impl RuleTable {
+    pub fn new() -> RuleTable {
+        RuleTable::default()
+    }
+
+    /// Add a rule to the table, replacing any rule with the same name.
+    pub fn insert<T: Into<String>>(&self, name: T, parser: Parser) {
+        self.rules.borrow_mut().insert(name.into(), parser);
+    }
+
+    /// Create a parser that parses the named rule, for use inside the rules of this table.
+    ///
+    /// The rule is looked up every time the parser runs. If the table has no rule with that
+    /// name, fail parsing. The parser only holds a weak reference to the table, so that rules
+    /// which call each other don't keep it alive. Running it after the table has been dropped
+    /// panics.
+    pub fn call<T: Into<String>>(&self, name: T) -> Parser {
+        let name: String = name.into();
+        let rules = Rc::downgrade(&self.rules);
+        let active = self.active.clone();
+        Parser::custom(move |s| {
+            let rules = rules
+                .upgrade()
+                .expect("RuleTable was dropped; use RuleTable::entry to keep it alive");
+            call_rule(&rules, &active, &name, s)
+        })
+    }
+
+    /// Create a parser that parses the named rule, keeping the table alive.
+    ///
+    /// Unlike `call`, the parser holds a strong reference to the table. Use it for the parser
+    /// that is returned to callers, not inside the table's own rules, where it would keep the
+    /// table alive forever.
+    pub fn entry<T: Into<String>>(&self, name: T) -> Parser {
+        let name: String = name.into();
+        let table = self.clone();
+        Parser::custom(move |s| call_rule(&table.rules, &table.active, &name, s))
+    }
+}
+
+/// Named integer counters that grammars can update and check at parse time.
+///
+/// Counters start at zero. Clones of a `Counters` share the same values.
+#[derive(Clone, Default)]
+pub struct Counters {
+    values: Rc<RefCell<HashMap<String, i64>>>,
+}
+impl Counters {
+    pub fn new() -> Counters {
+        Counters::default()
+    }
+
+    /// Get the current value of a counter.
+    pub fn get(&self, name: &str) -> i64 {
+        self.values.borrow().get(name).cloned().unwrap_or(0)
+    }
+
+    /// Create a parser that parses `body` with a counter incremented by one.
+    ///
+    /// The counter is restored once `body` finishes, whether or not it succeeded, so
+    /// backtracking never leaves a counter changed.
+    pub fn incr<T: Into<String>>(&self, name: T, body: Parser) -> Parser {
+        self.adjust(name.into(), 1, body)
+    }
+
+    /// Create a parser that parses `body` with a counter decremented by one.
+    ///
+    /// The counter is restored once `body` finishes, like with `incr`.
+    pub fn decr<T: Into<String>>(&self, name: T, body: Parser) -> Parser {
+        self.adjust(name.into(), -1, body)
+    }
+
+    /// Create a parser that succeeds without consuming input if a counter satisfies `predicate`.
+    ///
+    /// If the predicate returns false, fail parsing.
+    pub fn when<T: Into<String>, F>(&self, name: T, predicate: F) -> Parser
+    where
+        F: Fn(i64) -> bool + 'static,
+    {
+        let name: String = name.into();
+        let counters = self.clone();
+        Parser::custom(move |s| {
+            if predicate(counters.get(&name)) {
+                Ok(("".into(), s))
+            } else {
+                Err(s)
+            }
+        })
+    }
+
+    fn adjust(&self, name: String, by: i64, body: Parser) -> Parser {
+        let counters = self.clone();
+        Parser::custom(move |s| {
+            *counters
+                .values
+                .borrow_mut()
+                .entry(name.clone())
+                .or_insert(0) += by;
+            let result = body.parse(s);
+            *counters
+                .values
+                .borrow_mut()
+                .entry(name.clone())
+                .or_insert(0) -= by;
+            result
+        })
+    }
+}
+
+thread_local! {
+    // The indentation of the blocks being parsed by `indent_block`.
+    static INDENT_LEVELS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+fn indentation(s: &str) -> &str {
+    let len = s.find(|c| c != ' ' && c != '\t').unwrap_or(s.len());
+    &s[..len]
+}
+
+// Skip the lines at the start of `s` that are empty or only contain spaces and tabs.
+fn skip_blank_lines(s: &str) -> &str {
+    let mut rest = s;
+    loop {
+        let line_len = rest.find('\n').map_or(rest.len(), |i| i + 1);
+        let line = &rest[..line_len];
+        if line.is_empty() || !line.chars().all(|c| matches!(c, ' ' | '\t' | '\r' | '\n')) {
+            return rest;
+        }
+        rest = &rest[line_len..];
+    }
+}
+
+fn parse_indent_block(item: &Parser, s: String, indent: &str) -> Result<(String, String), String> {
+    let mut matched = vec![];
+    let mut rest = skip_blank_lines(&s).to_owned();
+    if rest.is_empty() {
+        return Err(s);
+    }
+    loop {
+        let (m, r) = item.parse(&rest[indent.len()..]).map_err(|_| s.clone())?;
+        matched.push(m);
+        let r = r
+            .strip_prefix("\r\n")
+            .or_else(|| r.strip_prefix('\n'))
+            .unwrap_or(&r)
+            .to_owned();
+        // The item has to make progress and end at the end of a line.
+        let consumed = &s[..s.len() - r.len()];
+        if r.len() == rest.len() || (!r.is_empty() && !consumed.ends_with('\n')) {
+            return Err(s);
+        }
+        rest = skip_blank_lines(&r).to_owned();
+        if rest.is_empty() {
+            break;
+        }
+        let line_indent = indentation(&rest);
+        if line_indent.len() < indent.len() && indent.starts_with(line_indent) {
+            break;
+        } else if line_indent != indent {
+            return Err(s);
+        }
+    }
+    Ok((to_string(&matched).unwrap(), rest))
+}
+
+fn call_rule(
+    rules: &RefCell<HashMap<String, Parser>>,
+    active: &RefCell<HashSet<(String, usize)>>,
+    name: &str,
+    s: String,
+) -> Result<(String, String), String> {
+    // Clone the rule so that the table isn't borrowed while it runs.
+    let rule = match rules.borrow().get(name) {
+        Some(rule) => rule.clone(),
+        None => return Err(s),
+    };
+    let key = (name.to_owned(), s.len());
+    if !active.borrow_mut().insert(key.clone()) {
+        panic!("left recursion in rule {:?}", name);
+    }
+    let result = rule.parse(s);
+    active.borrow_mut().remove(&key);
+    result
+}
+
+fn deserialize<T: DeserializeOwned>(matched: &str) -> Result<T, ron::Error> {
+    from_str::<T>(matched)
+        .or_else(|_| {
+            // Parsers like `and` return RON arrays, which only deserialize into tuples by way of a
+            // `ron::Value`.
+            from_str::<ron::Value>(matched)?.into_rust::<T>()
+        })
+        .or_else(|_| {
+            // Leaf results are raw text rather than RON, so try them as a string.
+            ron::Value::String(matched.to_owned()).into_rust::<T>()
+        })
+}
+
+// Serialize key-value pairs as a RON map, keeping their order.
+fn ron_map<'a, I>(entries: I) -> String
+where
+    I: Iterator<Item = (&'a String, &'a String)>,
+{
+    let entries = entries
+        .map(|(key, value)| format!("{}:{}", to_string(key).unwrap(), to_string(value).unwrap()))
+        .collect::<Vec<String>>();
+    format!("{{{}}}", entries.join(","))
+}
+
+// Parse one line of an outline, followed by the block of lines indented under it.
+fn outline_node(line: Parser) -> Parser {
+    Parser::custom(move |s| {
+        let (text, rest) = line.parse(s.clone()).map_err(|_| s.clone())?;
+        let mut node = OutlineNode {
+            text,
+            children: vec![],
+        };
+        let after_line_ending = rest
+            .strip_prefix("\r\n")
+            .or_else(|| rest.strip_prefix('\n'))
+            .map(|r| r.to_owned());
+        let rest = match after_line_ending {
+            Some(r) => match Parser::indent_block(outline_node(line.clone())).parse(r.clone()) {
+                Ok((children, r)) => {
+                    node.children = outline_nodes(&children).map_err(|_| s.clone())?;
+                    r
+                }
+                Err(_) => r,
+            },
+            // Let `indent_block` reject a line that wasn't fully parsed.
+            None => rest,
+        };
+        Ok((to_string(&node).unwrap(), rest))
+    })
+}
+
+// Deserialize the RON array of nodes returned by `outline_node` in an `indent_block`.
+fn outline_nodes(matched: &str) -> Result<Vec<OutlineNode>, ron::Error> {
+    from_str::<Vec<String>>(matched)?
+        .iter()
+        .map(|node| from_str::<OutlineNode>(node))
+        .collect()
+}
+
+// Like `Parser::map`, but returns the original input when the function fails.
+fn try_map<F>(parser: Parser, cfn: F) -> Parser
+where
+    F: Fn(String) -> Result<String, ron::Error> + 'static,
+{
+    Parser::custom(move |s| {
+        let (matched, rest) = parser.parse(s.clone())?;
+        match cfn(matched) {
+            Ok(m) => Ok((m, rest)),
+            Err(_) => Err(s),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(matched: &str, rest: &str) -> Result<(String, String), String> {
+        Ok((matched.into(), rest.into()))
+    }
+
+    // Wrap a parser so that every attempt to parse it is counted.
+    fn counting(parser: Parser) -> (Parser, Rc<std::cell::Cell<usize>>) {
+        let count = Rc::new(std::cell::Cell::new(0));
+        let counter = count.clone();
+        let parser = Parser::custom(move |s| {
+            counter.set(counter.get() + 1);
+            parser.parse(s)
+        });
+        (parser, count)
+    }
+
+    #[test]
+    fn split_on_drops_empty_pieces() {
+        let parser = Parser::split_on(",");
+        assert_eq!(parser.parse("a,,b,"), ok(r#"["a","b"]"#, ""));
+        assert_eq!(parser.parse(",a"), ok(r#"["a"]"#, ""));
+        assert_eq!(parser.parse(""), ok("[]", ""));
+    }
+
+    #[test]
+    fn many0_stops_at_empty_match() {
+        let parser = Parser::regex("a*").many0();
+        assert_eq!(parser.parse("aab"), ok(r#"["aa"]"#, "b"));
+        assert_eq!(parser.parse("b"), ok("[]", "b"));
+    }
+
+    #[test]
+    fn many1_requires_nonempty_match() {
+        let parser = Parser::regex("a*").many1();
+        assert_eq!(parser.parse("ab"), ok(r#"["a"]"#, "b"));
+        assert_eq!(parser.parse("b"), Err("b".into()));
+    }
+
+    #[test]
+    fn sum_totals_numbers() {
+        let parser = Parser::split_on(",").sum();
+        assert_eq!(parser.parse("1,2,3"), ok("6", ""));
+        assert_eq!(parser.parse("1.5,-0.5"), ok("1", ""));
+        assert_eq!(parser.parse(""), ok("0", ""));
+    }
+
+    #[test]
+    fn sum_rejects_non_numbers() {
+        let parser = Parser::split_on(",").sum();
+        assert_eq!(parser.parse("1,x,3"), Err("1,x,3".into()));
+        assert_eq!(parser.parse("inf"), Err("inf".into()));
+        assert_eq!(parser.parse("1,NaN"), Err("1,NaN".into()));
+        assert_eq!(parser.parse("1e308,1e308"), Err("1e308,1e308".into()));
+    }
+
+    #[test]
+    fn one_of_literals_prefers_longest() {
+        let parser = Parser::one_of_literals(&["<", "<=", "="]);
+        assert_eq!(parser.parse("<=1"), ok("<=", "1"));
+        assert_eq!(parser.parse("<1"), ok("<", "1"));
+    }
+
+    #[test]
+    fn one_of_literals_fails_without_match() {
+        let parser = Parser::one_of_literals(&["if", "while"]);
+        assert_eq!(parser.parse("for"), Err("for".into()));
+    }
+
+    #[test]
+    fn strip_bom_gives_same_result() {
+        let parser = Parser::regex("a").strip_bom();
+        assert_eq!(parser.parse("\u{FEFF}ab"), ok("a", "b"));
+        assert_eq!(parser.parse("ab"), ok("a", "b"));
+        assert_eq!(parser.parse("\u{FEFF}b"), Err("\u{FEFF}b".into()));
+    }
+
+    #[test]
+    fn and_one_of_parses_prefix_once() {
+        let (header, count) = counting(Parser::regex("HDR"));
+        let parser = header.and_one_of(vec![Parser::regex("A"), Parser::regex("B")]);
+        assert_eq!(parser.parse("HDRB!"), ok(r#"["HDR","B"]"#, "!"));
+        assert_eq!(count.get(), 1);
+        assert_eq!(parser.parse("HDRC"), Err("HDRC".into()));
+    }
+
+    #[test]
+    fn and_one_of_keeps_ignored_results() {
+        let parser = Parser::regex("HDR")
+            .ignore()
+            .and_one_of(vec![Parser::regex("A")]);
+        assert_eq!(parser.parse("HDRA"), ok(r#"["","A"]"#, ""));
+    }
+
+    #[test]
+    fn or_placeholder_records_error() {
+        let errors = Rc::new(RefCell::new(vec![]));
+        let parser = Parser::regex("a")
+            .or_placeholder("<error>", errors.clone())
+            .and(Parser::regex("b"));
+        assert_eq!(parser.parse("b"), ok(r#"["<error>","b"]"#, ""));
+        assert_eq!(*errors.borrow(), vec!["b".to_string()]);
+        assert_eq!(parser.parse("ab"), ok(r#"["a","b"]"#, ""));
+        assert_eq!(errors.borrow().len(), 1);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Date {
+        y: String,
+        m: String,
+        d: String,
+    }
+
+    #[test]
+    fn regex_to_struct_extracts_date() {
+        let parser = Parser::regex_to_struct::<Date>(r"(?P<y>\d{4})-(?P<m>\d{2})-(?P<d>\d{2})");
+        let (matched, rest) = parser.parse("2020-10-14 rest").unwrap();
+        assert_eq!(rest, " rest");
+        let date = Date {
+            y: "2020".into(),
+            m: "10".into(),
+            d: "14".into(),
+        };
+        assert_eq!(from_str::<Date>(&matched).unwrap(), date);
+        assert_eq!(parser.parse("x2020-10-14"), Err("x2020-10-14".into()));
+    }
+
+    #[test]
+    fn peek_n_does_not_consume() {
+        assert_eq!(Parser::peek_n(2).parse("äbc"), ok("äb", "äbc"));
+        assert_eq!(Parser::peek_n(5).parse("ab"), ok("ab", "ab"));
+        assert_eq!(Parser::peek_n(1).parse(""), ok("", ""));
+    }
+
+    #[test]
+    fn map_each_maps_every_element() {
+        let parser = Parser::split_on(",").map_each(|element| Ok(element.to_uppercase()));
+        assert_eq!(parser.parse("ab,c"), ok(r#"["AB","C"]"#, ""));
+        let failing = Parser::split_on(",").map_each(|element| match element.as_str() {
+            "x" => Err(ron::Error::custom("x")),
+            _ => Ok(element),
+        });
+        assert_eq!(failing.parse("a,x"), Err("a,x".into()));
+    }
+
+    #[test]
+    fn then_with_uses_count() {
+        let parser = Parser::regex("[0-9]+").then_with(|count| {
+            let count = count.parse::<usize>().unwrap();
+            Parser::regex("a").repeat_range(count..count)
+        });
+        assert_eq!(
+            parser.parse("3aaaa"),
+            ok(r#"["3","[\"a\",\"a\",\"a\"]"]"#, "a")
+        );
+        assert_eq!(parser.parse("3aa"), Err("3aa".into()));
+    }
+
+    #[test]
+    fn regex_ci_ignores_case_and_stays_anchored() {
+        let parser = Parser::regex_ci("select|from");
+        assert_eq!(parser.parse("SeLeCt x"), ok("SeLeCt", " x"));
+        assert_eq!(parser.parse("FROM"), ok("FROM", ""));
+        assert_eq!(parser.parse("x FROM"), Err("x FROM".into()));
+    }
+
+    #[test]
+    fn fail_lets_or_try_next_branch() {
+        assert_eq!(Parser::fail().parse("a"), Err("a".into()));
+        assert_eq!(
+            Parser::fail().or(Parser::regex("a")).parse("a"),
+            ok("a", "")
+        );
+    }
+
+    #[test]
+    fn succeed_consumes_nothing() {
+        let parser = Parser::succeed("x").and(Parser::regex("y"));
+        assert_eq!(parser.parse("y"), ok(r#"["x","y"]"#, ""));
+        assert_eq!(Parser::succeed("x").parse("z"), ok("x", "z"));
+    }
+
+    #[test]
+    fn repeat_while_stops_when_guard_fails() {
+        let parser = Parser::regex("[^\n]*\n?").repeat_while(Parser::regex("(?s)."));
+        assert_eq!(parser.parse("a\nb\n"), ok(r#"["a\n","b\n"]"#, ""));
+        assert_eq!(parser.parse(""), ok("[]", ""));
+    }
+
+    #[test]
+    fn repeat_while_fails_if_body_fails() {
+        let parser = Parser::regex("a").repeat_while(Parser::regex("[ab]"));
+        assert_eq!(parser.parse("aac"), ok(r#"["a","a"]"#, "c"));
+        assert_eq!(parser.parse("aab"), Err("aab".into()));
+    }
+
+    #[test]
+    fn indent_block_parses_consistent_block() {
+        let block = Parser::indent_block(Parser::regex("[a-z]+"));
+        assert_eq!(block.parse("  a\n  b\nc"), ok(r#"["a","b"]"#, "c"));
+        assert_eq!(block.parse("a\nb\n"), ok(r#"["a","b"]"#, ""));
+    }
+
+    #[test]
+    fn indent_block_rejects_inconsistent_indent() {
+        let block = Parser::indent_block(Parser::regex("[a-z]+"));
+        assert_eq!(block.parse("  a\n   b\nc"), Err("  a\n   b\nc".into()));
+        assert_eq!(block.parse("a b\n"), Err("a b\n".into()));
+    }
+
+    #[test]
+    fn indent_block_skips_blank_lines() {
+        let block = Parser::indent_block(Parser::regex("[a-z]+"));
+        assert_eq!(block.parse("a\n\nb\n"), ok(r#"["a","b"]"#, ""));
+        assert_eq!(block.parse("\n  a\n \t\n  b\n\nc"), ok(r#"["a","b"]"#, "c"));
+        assert_eq!(block.parse("a\n   "), ok(r#"["a"]"#, ""));
+        assert_eq!(block.parse("\n  \n"), Err("\n  \n".into()));
+    }
+
+    #[test]
+    fn present_reports_match() {
+        let parser = Parser::regex("pub ").present();
+        assert_eq!(parser.parse("pub fn"), ok("true", "fn"));
+        assert_eq!(parser.parse("fn"), ok("false", "fn"));
+    }
+
+    #[test]
+    fn balanced_brackets_nests_mixed_kinds() {
+        let parser = Parser::balanced_brackets();
+        assert_eq!(parser.parse("{a[(b)]c}d"), ok("a[(b)]c", "d"));
+        assert_eq!(parser.parse("()"), ok("", ""));
+    }
+
+    #[test]
+    fn balanced_brackets_rejects_unbalanced() {
+        let parser = Parser::balanced_brackets();
+        assert_eq!(parser.parse("(a[)]"), Err("(a[)]".into()));
+        assert_eq!(parser.parse("(a(b)"), Err("(a(b)".into()));
+        assert_eq!(parser.parse("a()"), Err("a()".into()));
+        assert_eq!(parser.parse(")"), Err(")".into()));
+    }
+
+    #[test]
+    fn all_matches_returns_every_branch() {
+        let parsers = vec![Parser::regex("ab"), Parser::regex("a"), Parser::regex("x")];
+        assert_eq!(
+            Parser::all_matches(parsers, "abc"),
+            vec![("ab".into(), "c".into()), ("a".into(), "bc".into())]
+        );
+    }
+
+    #[test]
+    fn skip_preamble_skips_bom_and_whitespace() {
+        let parser = Parser::regex("tok").skip_preamble(r"[ \n\r\t]", true);
+        assert_eq!(parser.parse("\u{FEFF}\n\n  tok!"), ok("tok", "!"));
+        assert_eq!(parser.parse("tok"), ok("tok", ""));
+    }
+
+    #[test]
+    fn skip_preamble_keeps_bom_when_disabled() {
+        let parser = Parser::regex("tok").skip_preamble(" ", false);
+        assert_eq!(parser.parse("\u{FEFF} tok"), Err("\u{FEFF} tok".into()));
+        assert_eq!(parser.parse("\ntok"), Err("\ntok".into()));
+    }
+
+    #[test]
+    fn parse_as_deserializes_and_pair() {
+        let parser = Parser::regex("a").and(Parser::regex("b"));
+        assert_eq!(
+            parser.parse_as::<(String, String), _>("ab"),
+            Ok(("a".to_string(), "b".to_string()))
+        );
+        assert_eq!(
+            parser.parse_as::<Vec<String>, _>("ab"),
+            Ok(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(
+            parser.parse_as::<(String, String), _>("ax"),
+            Err("ax".to_string())
+        );
+    }
+
+    #[test]
+    fn with_max_repeat_allows_up_to_max() {
+        let parser = Parser::regex("a").with_max_repeat(3);
+        assert_eq!(parser.parse("aab"), ok(r#"["a","a"]"#, "b"));
+        assert_eq!(parser.parse("aaab"), ok(r#"["a","a","a"]"#, "b"));
+        assert_eq!(parser.parse("b"), ok("[]", "b"));
+    }
+
+    #[test]
+    fn with_max_repeat_fails_past_max() {
+        let parser = Parser::regex("a").with_max_repeat(3);
+        assert_eq!(parser.parse("aaaab"), Err("aaaab".into()));
+        assert_eq!(
+            Parser::regex("a*").with_max_repeat(0).parse("b"),
+            ok("[]", "b")
+        );
+    }
+
+    #[test]
+    fn separated_by1_requires_one_element() {
+        let parser = Parser::regex("[0-9]+").separated_by1(Parser::regex(","));
+        assert_eq!(parser.parse("1"), ok(r#"["1"]"#, ""));
+        assert_eq!(parser.parse("1,2,3"), ok(r#"["1","2","3"]"#, ""));
+        assert_eq!(parser.parse(""), Err("".into()));
+        assert_eq!(parser.parse("1,2,"), ok(r#"["1","2"]"#, ","));
+    }
+
+    #[test]
+    fn separated_by0_allows_no_elements() {
+        let parser = Parser::regex("[0-9]+").separated_by0(Parser::regex(","));
+        assert_eq!(parser.parse("x"), ok("[]", "x"));
+        assert_eq!(parser.parse("1,2"), ok(r#"["1","2"]"#, ""));
+    }
+
+    #[test]
+    fn fields_names_elements() {
+        let parser = Parser::regex("[0-9]+")
+            .separated_by1(Parser::regex("-"))
+            .fields(&["y", "m", "d"]);
+        let (matched, _) = parser.parse("2020-10-14").unwrap();
+        assert_eq!(matched, r#"{"y":"2020","m":"10","d":"14"}"#);
+        let map = from_str::<HashMap<String, String>>(&matched).unwrap();
+        assert_eq!(map["m"], "10");
+    }
+
+    #[test]
+    fn fields_rejects_length_mismatch() {
+        let parser = Parser::regex("[0-9]+")
+            .separated_by1(Parser::regex("-"))
+            .fields(&["y", "m", "d"]);
+        assert_eq!(parser.parse("2020-10"), Err("2020-10".into()));
+        assert_eq!(parser.parse("2020-10-14-1"), Err("2020-10-14-1".into()));
+    }
+
+    #[test]
+    fn search_finds_marker_in_prose() {
+        let tag = Parser::regex(r"\[\[[a-z]+\]\]");
+        assert_eq!(
+            tag.clone().search(true).parse("see é [[tag]] here"),
+            ok(r#"["see é ","[[tag]]"]"#, " here")
+        );
+        assert_eq!(
+            tag.clone().search(false).parse("see [[tag]] here"),
+            ok("[[tag]]", " here")
+        );
+        assert_eq!(tag.search(false).parse("none"), Err("none".into()));
+    }
+
+    #[test]
+    fn regex_find_all_collects_matches() {
+        let parser = Parser::regex_find_all(r"[a-z]+@[a-z]+\.[a-z]+");
+        assert_eq!(
+            parser.parse("mail a@b.com or c@d.org!"),
+            ok(r#"["a@b.com","c@d.org"]"#, "")
+        );
+        assert_eq!(
+            Parser::regex_find_all("aa").parse("aaa"),
+            ok(r#"["aa"]"#, "")
+        );
+    }
+
+    // Rules `a` and `b` call each other: `a` is `x` or `(b)`, and `b` is `[a]`.
+    fn nested_brackets() -> Parser {
+        let table = RuleTable::new();
+        let a = Parser::regex(r"\(")
+            .ignore()
+            .and(table.call("b"))
+            .and(Parser::regex(r"\)").ignore())
+            .or(Parser::regex("x"));
+        let b = Parser::regex(r"\[")
+            .ignore()
+            .and(table.call("a"))
+            .and(Parser::regex(r"\]").ignore());
+        table.insert("a", a);
+        table.insert("b", b);
+        table.entry("a")
+    }
+
+    #[test]
+    fn rule_table_parses_mutual_recursion() {
+        let parser = nested_brackets();
+        assert_eq!(parser.parse("([([x])])!"), ok("x", "!"));
+        assert_eq!(parser.parse("([x)"), Err("([x)".into()));
+    }
+
+    #[test]
+    fn rule_table_fails_on_missing_rule() {
+        let table = RuleTable::new();
+        assert_eq!(table.call("missing").parse("x"), Err("x".into()));
+        assert_eq!(table.entry("missing").parse("x"), Err("x".into()));
+    }
+
+    #[test]
+    #[should_panic(expected = "RuleTable was dropped")]
+    fn rule_table_call_panics_after_drop() {
+        let parser = RuleTable::new().call("a");
+        let _ = parser.parse("x");
+    }
+
+    #[test]
+    fn seq_with_threads_accumulator() {
+        let steps: Vec<SeqStep<String>> = vec![
+            (
+                Parser::regex("<[a-z]+>"),
+                Box::new(|tag: &mut String, m: String| {
+                    *tag = m[1..m.len() - 1].to_owned();
+                    Ok(tag.clone())
+                }),
+            ),
+            (Parser::regex("[^<]*"), Box::new(|_: &mut String, m| Ok(m))),
+            (
+                Parser::regex("</[a-z]+>"),
+                Box::new(|tag: &mut String, m: String| {
+                    if m[2..m.len() - 1] == tag[..] {
+                        Ok(m)
+                    } else {
+                        Err(ron::Error::custom("mismatched tag"))
+                    }
+                }),
+            ),
+        ];
+        let parser = Parser::seq_with(String::new(), steps);
+        assert_eq!(parser.parse("<b>hi</b>"), ok(r#"["b","hi","</b>"]"#, ""));
+        assert_eq!(parser.parse("<b>hi</i>"), Err("<b>hi</i>".into()));
+    }
+
+    #[test]
+    fn deny_chars_rejects_raw_newline() {
+        let parser = Parser::regex(r#""[^"]*""#).deny_chars(char::is_control);
+        assert_eq!(parser.parse("\"a\nb\""), Err("\"a\nb\"".into()));
+        assert_eq!(parser.parse("\"ab\"x"), ok("\"ab\"", "x"));
+    }
+
+    #[test]
+    fn distinct_keeps_first_occurrence() {
+        let parser = Parser::regex("[a-z]")
+            .separated_by1(Parser::regex(","))
+            .distinct();
+        assert_eq!(parser.parse("a,b,a,c"), ok(r#"["a","b","c"]"#, ""));
+    }
+
+    #[test]
+    fn normalize_newlines_keeps_original_offsets() {
+        let line = Parser::regex("[a-zé]+\n");
+        let parser = line.clone().and(line.clone()).normalize_newlines();
+        let src = "é\r\nb\rc\r\n";
+        let (matched, rest) = parser.parse(src).unwrap();
+        assert_eq!(matched, r#"["é\n","b\n"]"#);
+        assert_eq!(rest, "c\r\n");
+        assert_eq!(src.len() - rest.len(), "é\r\nb\r".len());
+        let src = "a\r\nb\r\n1\r\n";
+        let parser = line.repeat_range(3..3).normalize_newlines();
+        assert_eq!(parser.parse(src), Err(src.into()));
+    }
+
+    #[test]
+    fn guarded_by_skips_subparser_when_guard_fails() {
+        let (number, count) = counting(Parser::regex("[0-9]+"));
+        let parser = number
+            .guarded_by(Parser::regex("[0-9]"))
+            .or(Parser::regex("[a-z]+"));
+        assert_eq!(parser.parse("abc"), ok("abc", ""));
+        assert_eq!(count.get(), 0);
+        assert_eq!(parser.parse("12"), ok("12", ""));
+        assert_eq!(count.get(), 1);
+    }
+
+    #[test]
+    fn parse_list_as_deserializes_numbers() {
+        let parser = Parser::regex("-?[0-9a-z]+").separated_by1(Parser::regex(","));
+        assert_eq!(
+            parser.parse_list_as::<i64, _>("1,-2,30"),
+            Ok(vec![1, -2, 30])
+        );
+        assert_eq!(parser.parse_list_as::<i64, _>("1,x"), Err("1,x".into()));
+    }
+
+    #[test]
+    fn parse_list_as_deserializes_strings() {
+        let parser = Parser::regex("[a-z]+").separated_by1(Parser::regex(","));
+        assert_eq!(
+            parser.parse_list_as::<String, _>("a,b"),
+            Ok(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(
+            Parser::regex("[a-z]+").parse_as::<String, _>("abc"),
+            Ok("abc".to_string())
+        );
+    }
+
+    #[test]
+    fn take_rest_consumes_everything() {
+        assert_eq!(Parser::take_rest().parse("ab c"), ok("ab c", ""));
+        assert_eq!(Parser::take_rest().parse(""), ok("", ""));
+    }
+
+    #[test]
+    fn repeat_min_progress_rejects_small_matches() {
+        let single = Parser::regex("[a-z]").repeat_min_progress(0..usize::MAX, 2);
+        assert_eq!(single.parse("abc"), Err("abc".into()));
+        let word = Parser::regex("[a-z]+").repeat_min_progress(0..usize::MAX, 2);
+        assert_eq!(word.parse("ab1"), ok(r#"["ab"]"#, "1"));
+        assert_eq!(word.parse("a"), Err("a".into()));
+    }
+
+    #[test]
+    fn repeat_min_progress_respects_range() {
+        let parser = Parser::regex("ab").repeat_min_progress(2..3, 2);
+        assert_eq!(parser.parse("ab"), Err("ab".into()));
+        assert_eq!(parser.parse("ababab"), ok(r#"["ab","ab","ab"]"#, ""));
+    }
+
+    #[test]
+    fn reparse_parses_quoted_csv_field() {
+        let quoted = Parser::regex("\"")
+            .ignore()
+            .and(Parser::regex("[^\"]*"))
+            .and(Parser::regex("\"").ignore());
+        let parser = quoted.reparse(Parser::regex("[a-z]+").separated_by1(Parser::regex(",")));
+        assert_eq!(parser.parse("\"a,b,c\";"), ok(r#"["a","b","c"]"#, ";"));
+        assert_eq!(parser.parse("\"a,b,\";"), Err("\"a,b,\";".into()));
+    }
+
+    #[test]
+    fn parse_lines_reports_failing_line() {
+        let number = Parser::regex("[0-9]+");
+        assert_eq!(number.parse_lines("1\nx\n3"), Err(1));
+        assert_eq!(
+            number.parse_lines("1\r\n2\n"),
+            Ok(vec!["1".to_string(), "2".to_string()])
+        );
+    }
+
+    #[test]
+    fn lines_collects_each_line() {
+        let parser = Parser::lines(Parser::regex("[0-9]+"));
+        assert_eq!(parser.parse("1\r\n2\n"), ok(r#"["1","2"]"#, ""));
+        assert_eq!(parser.parse("1\n2a"), Err("1\n2a".into()));
+    }
+
+    #[test]
+    fn counters_limit_nesting_depth() {
+        let counters = Counters::new();
+        let table = RuleTable::new();
+        let nested = counters
+            .when("depth", |depth| depth <= 3)
+            .ignore()
+            .and(table.call("group").optional());
+        let group = Parser::regex(r"\(")
+            .ignore()
+            .and(counters.incr("depth", nested))
+            .and(Parser::regex(r"\)").ignore());
+        table.insert("group", group);
+        let parser = table.entry("group").or_fallback(Parser::fail());
+        assert!(parser.parse("((()))").is_ok());
+        assert_eq!(parser.parse("(((())))"), Err("(((())))".into()));
+        assert_eq!(counters.get("depth"), 0);
+    }
+
+    #[test]
+    fn case_conversion_is_unicode_aware() {
+        let word = Parser::regex(r"\w+");
+        assert_eq!(
+            word.clone().to_lowercase().parse("SeLECT"),
+            ok("select", "")
+        );
+        assert_eq!(
+            word.clone().to_uppercase().parse("select"),
+            ok("SELECT", "")
+        );
+        assert_eq!(word.clone().to_lowercase().parse("İ"), ok("i\u{307}", ""));
+        assert_eq!(word.to_uppercase().parse("ß"), ok("SS", ""));
+    }
+
+    #[test]
+    fn separated_by_uniform_rejects_mixed_separators() {
+        let parser = Parser::regex("[0-9]+").separated_by_uniform(Parser::regex("[,;]"));
+        assert_eq!(parser.parse("1;2;3"), ok(r#"["1","2","3"]"#, ""));
+        assert_eq!(parser.parse("1,2;3"), Err("1,2;3".into()));
+        assert_eq!(parser.parse("1,2;"), ok(r#"["1","2"]"#, ";"));
+    }
+
+    #[test]
+    fn indexed_prefixes_elements() {
+        let parser = Parser::regex("[a-z]").many0().indexed();
+        assert_eq!(parser.parse("abc"), ok(r#"["0:a","1:b","2:c"]"#, ""));
+    }
+
+    #[test]
+    fn min_len_and_max_len_bound_matches() {
+        let parser = Parser::regex("[a-z]+").min_len(2).max_len(4);
+        assert_eq!(parser.parse("a1"), Err("a1".into()));
+        assert_eq!(parser.parse("ab1"), ok("ab", "1"));
+        assert_eq!(parser.parse("abcd1"), ok("abcd", "1"));
+        assert_eq!(parser.parse("abcde1"), Err("abcde1".into()));
+    }
+
+    #[test]
+    fn parse_many_collects_each_result() {
+        let results = Parser::regex("[0-9]+").parse_many(vec!["1", "x", "23"]);
+        assert_eq!(results, vec![ok("1", ""), Err("x".into()), ok("23", "")]);
+    }
+
+    #[test]
+    fn csv_row_handles_quotes() {
+        let parser = Parser::csv_row();
+        assert_eq!(parser.parse("a,b,c\nnext"), ok(r#"["a","b","c"]"#, "next"));
+        assert_eq!(parser.parse("a,\"b,c\",d"), ok(r#"["a","b,c","d"]"#, ""));
+        assert_eq!(
+            parser.parse("\"say \"\"hi\"\"\",x\r\n"),
+            ok(r#"["say \"hi\"","x"]"#, "")
+        );
+        assert_eq!(parser.parse("a,,"), ok(r#"["a","",""]"#, ""));
+    }
+
+    #[test]
+    fn csv_row_rejects_bad_quotes() {
+        let parser = Parser::csv_row();
+        assert_eq!(parser.parse("\"open"), Err("\"open".into()));
+        assert_eq!(parser.parse("\"a\"b"), Err("\"a\"b".into()));
+    }
+
+    #[test]
+    fn and_ws_requires_whitespace() {
+        let parser = Parser::regex("a").and_ws(Parser::regex("b"));
+        assert_eq!(parser.parse("ab"), Err("ab".into()));
+        assert_eq!(parser.parse("a b"), ok(r#"["a","b"]"#, ""));
+        assert_eq!(parser.parse("a \t b!"), ok(r#"["a","b"]"#, "!"));
+    }
+
+    #[test]
+    fn and_ws_keeps_ignored_results() {
+        let parser = Parser::regex("a").ignore().and_ws(Parser::regex("b"));
+        assert_eq!(parser.parse("a b"), ok(r#"["","b"]"#, ""));
+    }
+
+    #[test]
+    fn cache_failures_attempts_each_position_once() {
+        let (keyword, count) = counting(Parser::regex("kw"));
+        let keyword = keyword.cache_failures();
+        let parser = keyword
+            .clone()
+            .and(Parser::regex("1"))
+            .or(keyword.clone().and(Parser::regex("2")))
+            .or(keyword.or(Parser::regex("x")));
+        assert_eq!(parser.parse("x"), ok("x", ""));
+        assert_eq!(count.get(), 1);
+    }
+
+    #[test]
+    fn cache_failures_resets_for_new_input() {
+        let (keyword, count) = counting(Parser::regex("kw"));
+        let parser = keyword.cache_failures().or(Parser::regex("[a-z]+"));
+        assert_eq!(parser.parse("x"), ok("x", ""));
+        assert_eq!(parser.parse("kw"), ok("kw", ""));
+        assert_eq!(parser.parse("y"), ok("y", ""));
+        assert_eq!(count.get(), 3);
+        // Parsing the same input again reuses the cache.
+        assert_eq!(parser.parse("y"), ok("y", ""));
+        assert_eq!(count.get(), 3);
+    }
+
+    fn query_pairs(duplicates: DuplicateKeys) -> Parser {
+        let word = Parser::regex("[a-z0-9]+");
+        word.clone()
+            .and(Parser::regex("=").ignore())
+            .and(word)
+            .key_values(Parser::regex("&"), duplicates)
+    }
+
+    #[test]
+    fn key_values_first_wins() {
+        let parser = query_pairs(DuplicateKeys::FirstWins);
+        assert_eq!(parser.parse("a=1&b=2&a=3"), ok(r#"{"a":"1","b":"2"}"#, ""));
+    }
+
+    #[test]
+    fn key_values_last_wins() {
+        let parser = query_pairs(DuplicateKeys::LastWins);
+        assert_eq!(parser.parse("a=1&b=2&a=3"), ok(r#"{"a":"3","b":"2"}"#, ""));
+    }
+
+    #[test]
+    fn key_values_error_on_duplicate() {
+        let parser = query_pairs(DuplicateKeys::Error);
+        assert_eq!(parser.parse("a=1&b=2&a=3"), Err("a=1&b=2&a=3".into()));
+        assert_eq!(parser.parse("a=1&b=2"), ok(r#"{"a":"1","b":"2"}"#, ""));
+    }
+
+    #[test]
+    fn unquote_strips_matching_quotes() {
+        let parser = Parser::regex(r#""[^"]*"|'[^']*'|[a-z"]+"#).unquote();
+        assert_eq!(parser.parse("\"a b\""), ok("a b", ""));
+        assert_eq!(parser.parse("'a'"), ok("a", ""));
+        assert_eq!(parser.parse("abc"), ok("abc", ""));
+        assert_eq!(parser.parse("\""), ok("\"", ""));
+    }
+
+    #[test]
+    fn or_fallback_requires_whole_input() {
+        let current = Parser::regex("v2:").ignore().and(Parser::regex("[a-z]+"));
+        let legacy = Parser::regex("[a-z]+").and(Parser::regex(";").ignore());
+        let parser = current.or_fallback(legacy);
+        assert_eq!(parser.parse("v2:abc"), ok("abc", ""));
+        assert_eq!(parser.parse("abc;"), ok("abc", ""));
+        assert_eq!(parser.parse("v2:abc;"), Err("v2:abc;".into()));
+    }
+
+    fn node(text: &str, children: Vec<OutlineNode>) -> OutlineNode {
+        OutlineNode {
+            text: text.into(),
+            children,
+        }
+    }
+
+    #[test]
+    fn outline_builds_nested_tree() {
+        let parser = Parser::outline(Parser::regex("[^\n]+"));
+        let tree = parser
+            .parse_as::<Vec<OutlineNode>, _>("a\n  b\n  c\n    d\ne\n")
+            .unwrap();
+        let expected = vec![
+            node(
+                "a",
+                vec![node("b", vec![]), node("c", vec![node("d", vec![])])],
+            ),
+            node("e", vec![]),
+        ];
+        assert_eq!(tree, expected);
+    }
+
+    #[test]
+    fn outline_skips_blank_lines_between_parents() {
+        let parser = Parser::outline(Parser::regex("[^\n]+"));
+        let tree = parser
+            .parse_as::<Vec<OutlineNode>, _>("a\n  b\n\nc\n\n  d\n")
+            .unwrap();
+        let expected = vec![
+            node("a", vec![node("b", vec![])]),
+            node("c", vec![node("d", vec![])]),
+        ];
+        assert_eq!(tree, expected);
+    }
+
+    #[test]
+    fn outline_rejects_inconsistent_indent() {
+        let parser = Parser::outline(Parser::regex("[a-z ]+"));
+        assert!(parser.parse("a\n  b\n c\n").is_err());
+        assert!(Parser::outline(Parser::regex("[a-z]+"))
+            .parse("ab cd\n")
+            .is_err());
+    }
+
+    #[test]
+    fn indent_block_rejects_mixed_tabs_and_spaces() {
+        let block = Parser::indent_block(Parser::regex("[a-z]+"));
+        assert_eq!(block.parse("\ta\n b\n"), Err("\ta\n b\n".into()));
+        assert_eq!(block.parse("\ta\n\tb\nc"), ok(r#"["a","b"]"#, "c"));
+        let outline = Parser::outline(Parser::regex("[a-z]+"));
+        assert!(outline.parse("a\n\tb\n  c\n").is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "left recursion in rule \"a\"")]
+    fn rule_table_panics_on_left_recursion() {
+        let table = RuleTable::new();
+        let a = table
+            .call("a")
+            .and(Parser::regex("x"))
+            .or(Parser::regex("x"));
+        table.insert("a", a);
+        let _ = table.entry("a").parse("xx");
+    }
+
+    #[test]
+    fn rule_table_allows_recursion_after_progress() {
+        let table = RuleTable::new();
+        table.insert(
+            "list",
+            Parser::regex("x").and(table.call("list").optional()),
+        );
+        let parser = table.entry("list");
+        assert_eq!(parser.parse("xxy").map(|(_, rest)| rest), Ok("y".into()));
+        // The rules that were active are cleared, so parsing the same input again works.
+        assert_eq!(parser.parse("xxy").map(|(_, rest)| rest), Ok("y".into()));
+    }
+
+    #[test]
+    fn min_consumed_rejects_all_optional_grammar() {
+        let grammar = Parser::regex("a").optional().min_consumed();
+        assert_eq!(grammar.parse("b"), Err("b".into()));
+        assert_eq!(grammar.parse("ab"), ok(r#"["a"]"#, "b"));
+    }
+
+    #[test]
+    fn separated_by_uniform_compares_ignored_separators() {
+        let parser = Parser::regex("[0-9]+").separated_by_uniform(Parser::regex("[,;]").ignore());
+        assert_eq!(parser.parse("1,2,3"), ok(r#"["1","2","3"]"#, ""));
+        assert_eq!(parser.parse("1,2;3"), Err("1,2;3".into()));
+    }
+}
@@ -1,5 +1,6 @@
 pub mod ast;
 pub mod codegen;
+pub mod combinators;
 pub mod parse;
 
 use codegen::{SymbolGenerator, Wasm};